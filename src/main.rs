@@ -1,11 +1,12 @@
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 use rand_distr::{Distribution, Normal};
 use regex::Regex;
 use serde_json::{json, Value};
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    fs::{File, OpenOptions},
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    fs::{read_to_string, File, OpenOptions},
     io::Write,
 };
 
@@ -51,7 +52,13 @@ struct CBOWParams {
     window_size: usize,
     target: usize,
     learning_rate: f32,
+    min_learning_rate: Option<f32>,
     epochs: usize,
+    subword: bool,
+    min_n: usize,
+    max_n: usize,
+    buckets: usize,
+    threads: usize,
 }
 impl CBOWParams {
     fn set_random_samples(mut self, random_samples: usize) -> Self {
@@ -83,6 +90,40 @@ impl CBOWParams {
         self.epochs = epochs;
         self
     }
+    /// Floor for linear learning-rate decay. Without it the rate stays fixed at
+    /// `learning_rate`; with it the rate interpolates from `learning_rate` down
+    /// to this floor as epochs progress, the standard word2vec schedule.
+    fn set_min_learning_rate(mut self, min_learning_rate: f32) -> Self {
+        self.min_learning_rate = Some(min_learning_rate);
+        self
+    }
+    /// Learning rate for `epoch`, linearly interpolated from `learning_rate`
+    /// down to `min_learning_rate` (or constant when no floor is set).
+    fn learning_rate_at(&self, epoch: usize) -> f32 {
+        match self.min_learning_rate {
+            Some(floor) if self.epochs > 1 => {
+                let progress = epoch as f32 / (self.epochs - 1) as f32;
+                self.learning_rate - (self.learning_rate - floor) * progress
+            }
+            _ => self.learning_rate,
+        }
+    }
+    /// Enable fastText-style subword embeddings: each token also contributes the
+    /// hashed character n-grams of length `min_n..=max_n` from a pool of
+    /// `buckets` bucket rows appended to the input matrix.
+    fn set_subword(mut self, min_n: usize, max_n: usize, buckets: usize) -> Self {
+        self.subword = true;
+        self.min_n = min_n;
+        self.max_n = max_n;
+        self.buckets = buckets;
+        self
+    }
+    /// Number of worker threads for Hogwild training. `n == 1` keeps the serial
+    /// path.
+    fn set_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
     fn default() -> Self {
         let window_size = 2;
         Self {
@@ -94,7 +135,13 @@ impl CBOWParams {
             window_size: window_size * 2 + 1,
             target: window_size,
             learning_rate: 0.01,
+            min_learning_rate: None,
             epochs: 100,
+            subword: false,
+            min_n: 3,
+            max_n: 6,
+            buckets: 2_000_000,
+            threads: 1,
         }
     }
     fn new(vocab_size: usize) -> Self {
@@ -103,11 +150,21 @@ impl CBOWParams {
         result
     }
 
+    /// Number of input-matrix rows: one per vocabulary word, plus the subword
+    /// bucket region when subword mode is on.
+    fn input_rows_count(&self) -> usize {
+        if self.subword {
+            self.vocab_size + self.buckets
+        } else {
+            self.vocab_size
+        }
+    }
+
     fn create_matrices(&self) -> (Vec<f32>, Vec<f32>) {
         // set the embeddings_dimension from and type
         let normal = Normal::new(self.mean, self.std_dev).unwrap();
         let mut rng = thread_rng();
-        let input_matrix: Vec<f32> = (0..self.vocab_size)
+        let input_matrix: Vec<f32> = (0..self.input_rows_count())
             .flat_map(|_| {
                 (0..self.embeddings_dimension)
                     .map(|_| normal.sample(&mut rng))
@@ -119,6 +176,28 @@ impl CBOWParams {
         (input_matrix, output_matrix)
     }
 
+    /// For every vocabulary word, the input-matrix rows that make up its
+    /// embedding: its own row, followed by its subword bucket rows when subword
+    /// mode is on. Without subword mode each word maps to just its own row, so
+    /// callers behave exactly as before.
+    fn input_rows(&self, corpus: &CorpusValues) -> Vec<Vec<usize>> {
+        let mut rows = vec![Vec::new(); self.vocab_size];
+        for (word, index) in &corpus.words_map {
+            let mut word_rows = vec![*index];
+            if self.subword {
+                word_rows.extend(subword_indices(
+                    word,
+                    self.min_n,
+                    self.max_n,
+                    self.buckets,
+                    self.vocab_size,
+                ));
+            }
+            rows[*index] = word_rows;
+        }
+        rows
+    }
+
     fn get_random_indices(&self, target: &usize, corpus: &[usize]) -> Vec<usize> {
         let mut rng = thread_rng();
         corpus
@@ -148,78 +227,799 @@ fn parse_corpus(mut corpus: String) -> CorpusValues {
     CorpusValues::new().populate(clean_corpus)
 }
 
+/// 32-bit FNV-1a hash, used to bucket character n-grams into the subword
+/// region of the input matrix.
+fn fnv1a(ngram: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in ngram.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Absolute input-matrix row indices of a word's character n-grams. The word is
+/// wrapped in boundary markers (`<word>`), every n-gram of length
+/// `min_n..=max_n` is hashed into one of `buckets` buckets, and the result is
+/// offset past the `vocab_size` word rows into the bucket region.
+fn subword_indices(
+    word: &str,
+    min_n: usize,
+    max_n: usize,
+    buckets: usize,
+    vocab_size: usize,
+) -> Vec<usize> {
+    let wrapped = format!("<{word}>");
+    let chars: Vec<char> = wrapped.chars().collect();
+    let mut indices = Vec::new();
+    for n in min_n..=max_n {
+        if chars.len() < n {
+            continue;
+        }
+        for window in chars.windows(n) {
+            let ngram: String = window.iter().collect();
+            indices.push(vocab_size + (fnv1a(&ngram) as usize % buckets));
+        }
+    }
+    indices
+}
+
 fn get_context_embedding(
     context_indices: &[usize],
     embeddings_dimension: usize,
     embeddings: &[f32],
+    input_rows: &[Vec<usize>],
 ) -> Vec<f32> {
     //TODO: perform either sum or average, currently only the sum.
-    // Sum
-    (0..embeddings_dimension)
-        .map(|position| {
-            context_indices
-                .iter()
-                .map(|context_index| embeddings[position + *context_index * embeddings_dimension])
-                .sum()
-        })
-        .collect()
+    // Sum the context words; each word is itself the average of its own row and
+    // (in subword mode) its bucket rows.
+    let mut result = vec![0.0; embeddings_dimension];
+    for context_index in context_indices {
+        let rows = &input_rows[*context_index];
+        let scale = 1.0 / rows.len() as f32;
+        for row in rows {
+            let offset = row * embeddings_dimension;
+            for (position, value) in result.iter_mut().enumerate() {
+                *value += embeddings[offset + position] * scale;
+            }
+        }
+    }
+    result
 }
 
 fn sigmoid(x: f32) -> f32 {
     1.0 / (1.0 + (-x).exp())
 }
 
+/// Raw `ptr`/`len` view of a matrix shared across Hogwild worker threads. It
+/// deliberately hands out no `&mut` itself; each worker rebuilds its own slice
+/// from the raw parts at the point of use, confining the aliasing to the write
+/// loop. Updates touch mostly-disjoint rows, so the occasional racy write is
+/// tolerated as noise rather than guarded by a lock.
+#[derive(Clone, Copy)]
+struct SharedMatrix {
+    ptr: *mut f32,
+    len: usize,
+}
+unsafe impl Send for SharedMatrix {}
+unsafe impl Sync for SharedMatrix {}
+
+/// A single CBOW forward/negative-sampling/backprop update for one pair. Shared
+/// by the serial and Hogwild paths so both run the exact same arithmetic.
+#[allow(clippy::too_many_arguments)]
+fn train_pair(
+    context: &[usize],
+    target: &usize,
+    cbow_params: &CBOWParams,
+    learning_rate: f32,
+    input_layer: &mut [f32],
+    hidden_layer: &mut [f32],
+    input_rows: &[Vec<usize>],
+    corpus: &CorpusValues,
+) -> f32 {
+    // pass the input layer to the hidden layer
+    let neu1 = get_context_embedding(
+        context,
+        cbow_params.embeddings_dimension,
+        input_layer,
+        input_rows,
+    );
+
+    // negative sampling
+    let target_l2 = target * cbow_params.embeddings_dimension;
+    let f: f32 = neu1
+        .iter()
+        .enumerate()
+        .map(|(i, v)| v * hidden_layer[i + target_l2])
+        .sum();
+
+    // negative-sampling loss for the positive target
+    let mut loss = -sigmoid(f).ln();
+    let g = (1.0 - sigmoid(f)) * learning_rate;
+
+    let mut neu1e: Vec<f32> = (0..cbow_params.embeddings_dimension)
+        .map(|c| g * hidden_layer[c + target_l2])
+        .collect();
+    (0..cbow_params.embeddings_dimension)
+        .for_each(|c| hidden_layer[c + target_l2] += g * neu1[c]);
+
+    for negative_target in cbow_params.get_random_indices(target, &corpus.vec) {
+        let l2 = negative_target * cbow_params.embeddings_dimension;
+        let f: f32 = neu1
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v * hidden_layer[i + l2])
+            .sum();
+
+        loss -= sigmoid(-f).ln();
+        let g = (0.0 - sigmoid(f)) * learning_rate;
+
+        (0..cbow_params.embeddings_dimension).for_each(|c| neu1e[c] += g * hidden_layer[c + l2]);
+        (0..cbow_params.embeddings_dimension).for_each(|c| hidden_layer[c + l2] += g * neu1[c]);
+    }
+
+    // backpropagation, pass the hidden layer to the input layer. The
+    // gradient flows into the word row and each of its bucket rows.
+    context.iter().for_each(|index| {
+        input_rows[*index].iter().for_each(|row| {
+            (0..cbow_params.embeddings_dimension)
+                .for_each(|c| input_layer[c + row * cbow_params.embeddings_dimension] += neu1e[c])
+        })
+    });
+
+    loss
+}
+
 fn train(
     pairs: &[(Vec<usize>, usize)],
     cbow_params: &CBOWParams,
-    input_layer: &mut Vec<f32>,
-    mut hidden_layer: Vec<f32>,
+    input_layer: &mut [f32],
+    hidden_layer: &mut [f32],
     corpus: &CorpusValues,
+    mut on_epoch: impl FnMut(usize, f32),
 ) {
-    for _ in 0..cbow_params.epochs {
-        for (context, target) in pairs {
-            // pass the input layer to the hidden layer
-            let neu1 =
-                get_context_embedding(&context, cbow_params.embeddings_dimension, &input_layer);
-
-            // negative sampling
-            let target_l2 = target * cbow_params.embeddings_dimension;
-            let f: f32 = neu1
+    let input_rows = cbow_params.input_rows(corpus);
+
+    if cbow_params.threads <= 1 {
+        for epoch in 0..cbow_params.epochs {
+            let learning_rate = cbow_params.learning_rate_at(epoch);
+            let mut total_loss = 0.0;
+            for (context, target) in pairs {
+                total_loss += train_pair(
+                    context,
+                    target,
+                    cbow_params,
+                    learning_rate,
+                    input_layer,
+                    hidden_layer,
+                    &input_rows,
+                    corpus,
+                );
+            }
+            on_epoch(epoch, total_loss / pairs.len() as f32);
+        }
+        return;
+    }
+
+    // Hogwild: every thread mutates the shared matrices in place with no locks.
+    let input_shared = SharedMatrix {
+        ptr: input_layer.as_mut_ptr(),
+        len: input_layer.len(),
+    };
+    let hidden_shared = SharedMatrix {
+        ptr: hidden_layer.as_mut_ptr(),
+        len: hidden_layer.len(),
+    };
+    let shard_size = pairs.len().div_ceil(cbow_params.threads).max(1);
+
+    // Each shard accumulates its per-epoch loss independently; the sums are
+    // merged after the join so `on_epoch` still fires once per epoch. The
+    // totals are inherently racy under Hogwild (concurrent readers see each
+    // other's partial updates), but they track convergence well enough to log.
+    let per_shard_losses = std::thread::scope(|scope| {
+        let handles: Vec<_> = pairs
+            .chunks(shard_size)
+            .map(|shard| {
+                let input_rows = &input_rows;
+                scope.spawn(move || {
+                    // SAFETY: each shard writes mostly-disjoint rows of the shared
+                    // matrices; the rare overlapping write is the tolerated Hogwild
+                    // race, and the backing Vecs outlive this scope.
+                    let input_layer = unsafe {
+                        std::slice::from_raw_parts_mut(input_shared.ptr, input_shared.len)
+                    };
+                    let hidden_layer = unsafe {
+                        std::slice::from_raw_parts_mut(hidden_shared.ptr, hidden_shared.len)
+                    };
+                    let mut shard_losses = vec![0.0f32; cbow_params.epochs];
+                    for epoch in 0..cbow_params.epochs {
+                        let learning_rate = cbow_params.learning_rate_at(epoch);
+                        for (context, target) in shard {
+                            shard_losses[epoch] += train_pair(
+                                context,
+                                target,
+                                cbow_params,
+                                learning_rate,
+                                input_layer,
+                                hidden_layer,
+                                input_rows,
+                                corpus,
+                            );
+                        }
+                    }
+                    shard_losses
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    for epoch in 0..cbow_params.epochs {
+        let total_loss: f32 = per_shard_losses.iter().map(|losses| losses[epoch]).sum();
+        on_epoch(epoch, total_loss / pairs.len() as f32);
+    }
+}
+
+/// A single scored row, ordered by cosine similarity so it can live in a
+/// `BinaryHeap` for the top-k partial sort.
+struct Scored {
+    index: usize,
+    score: f32,
+}
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.score.eq(&other.score)
+    }
+}
+impl Eq for Scored {}
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score)
+    }
+}
+
+/// Subword configuration retained alongside trained vectors so out-of-vocabulary
+/// words can be embedded from their character n-gram bucket rows at query time.
+struct Subword {
+    min_n: usize,
+    max_n: usize,
+    buckets: usize,
+    vocab_size: usize,
+}
+
+/// Trained vectors made queryable. Wraps the `words_map` produced while parsing
+/// the corpus together with the `input_layer` matrix. [`Embeddings::new`]
+/// stores the matrix row-normalized; [`Embeddings::from_rows`] keeps rows as
+/// given. Similarity queries divide by the norms, so both are ranked by cosine
+/// similarity.
+struct Embeddings {
+    words_map: HashMap<String, usize>,
+    matrix: Vec<f32>,
+    embeddings_dimension: usize,
+    subword: Option<Subword>,
+}
+
+impl Embeddings {
+    /// Build from a trained `input_layer`, normalizing every
+    /// `embeddings_dimension`-length row to unit length. Rows whose norm is
+    /// zero are left untouched to avoid dividing by zero.
+    fn new(
+        words_map: HashMap<String, usize>,
+        input_layer: Vec<f32>,
+        embeddings_dimension: usize,
+    ) -> Self {
+        let mut matrix = input_layer;
+        for row in matrix.chunks_mut(embeddings_dimension) {
+            let norm = row.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                row.iter_mut().for_each(|v| *v /= norm);
+            }
+        }
+        Self {
+            words_map,
+            matrix,
+            embeddings_dimension,
+            subword: None,
+        }
+    }
+
+    /// Build from rows that are already in their final form, storing the matrix
+    /// verbatim with no re-normalization. The word2vec readers use this so
+    /// loaded vectors round-trip the bytes on disk and externally pretrained
+    /// (non-unit-norm) vectors keep their original scale.
+    fn from_rows(
+        words_map: HashMap<String, usize>,
+        matrix: Vec<f32>,
+        embeddings_dimension: usize,
+    ) -> Self {
+        Self {
+            words_map,
+            matrix,
+            embeddings_dimension,
+            subword: None,
+        }
+    }
+
+    /// Like [`Embeddings::new`] but keeps the subword configuration and the
+    /// bucket rows stored after the vocabulary rows of `input_layer`, so unknown
+    /// words can still be embedded from their n-grams.
+    fn with_subword(
+        words_map: HashMap<String, usize>,
+        input_layer: Vec<f32>,
+        embeddings_dimension: usize,
+        subword: Subword,
+    ) -> Self {
+        let mut embeddings = Self::new(words_map, input_layer, embeddings_dimension);
+        embeddings.subword = Some(subword);
+        embeddings
+    }
+
+    /// The embedding of `word`: its own stored row when known, otherwise the
+    /// normalized sum of its subword bucket rows when subword mode is enabled.
+    /// Returns `None` for an unknown word with no subword information.
+    fn embedding_for(&self, word: &str) -> Option<Vec<f32>> {
+        if let Some(index) = self.words_map.get(word) {
+            return Some(self.row(*index).to_vec());
+        }
+        let subword = self.subword.as_ref()?;
+        let mut vector = vec![0.0; self.embeddings_dimension];
+        for row in subword_indices(
+            word,
+            subword.min_n,
+            subword.max_n,
+            subword.buckets,
+            subword.vocab_size,
+        ) {
+            let offset = row * self.embeddings_dimension;
+            for (position, value) in vector.iter_mut().enumerate() {
+                *value += self.matrix[offset + position];
+            }
+        }
+        let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|v| *v /= norm);
+        }
+        Some(vector)
+    }
+
+    fn row(&self, index: usize) -> &[f32] {
+        let start = index * self.embeddings_dimension;
+        &self.matrix[start..start + self.embeddings_dimension]
+    }
+
+    /// The `k` words with the highest cosine similarity to `word`, excluding the
+    /// query word itself. Returns an empty vector when the word is unknown and
+    /// there is no subword information to embed it from.
+    fn most_similar(&self, word: &str, k: usize) -> Vec<(String, f32)> {
+        let query = match self.embedding_for(word) {
+            Some(query) => query,
+            None => return Vec::new(),
+        };
+        let exclude: Vec<usize> = self.words_map.get(word).copied().into_iter().collect();
+        self.top_k(query, k, &exclude)
+    }
+
+    /// Solve `a : b :: c : ?` by scoring every word against the normalized
+    /// target vector `vec(b) - vec(a) + vec(c)`, excluding the three inputs.
+    fn analogy(&self, a: &str, b: &str, c: &str, k: usize) -> Vec<(String, f32)> {
+        let (ia, ib, ic) = match (
+            self.words_map.get(a),
+            self.words_map.get(b),
+            self.words_map.get(c),
+        ) {
+            (Some(ia), Some(ib), Some(ic)) => (*ia, *ib, *ic),
+            _ => return Vec::new(),
+        };
+        let mut target: Vec<f32> = (0..self.embeddings_dimension)
+            .map(|c| self.row(ib)[c] - self.row(ia)[c] + self.row(ic)[c])
+            .collect();
+        let norm = target.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            target.iter_mut().for_each(|v| *v /= norm);
+        }
+        self.top_k(target, k, &[ia, ib, ic])
+    }
+
+    /// Words ordered by their row index, so serializers emit rows and the
+    /// vocabulary header in a single consistent order.
+    fn words_by_index(&self) -> Vec<&String> {
+        let mut words = vec![None; self.words_map.len()];
+        for (word, index) in &self.words_map {
+            words[*index] = Some(word);
+        }
+        words.into_iter().map(|w| w.unwrap()).collect()
+    }
+
+    /// Write the vectors in the canonical word2vec text format: a
+    /// `"<vocab_size> <dim>"` header followed by one `"<word> <f0> <f1> ..."`
+    /// line per entry.
+    fn save_word2vec_text(&self, file_path: &str) {
+        let mut file = open_or_create_file(file_path);
+        let vocab_size = self.words_map.len();
+        writeln!(file, "{} {}", vocab_size, self.embeddings_dimension).expect("write header");
+        for (index, word) in self.words_by_index().into_iter().enumerate() {
+            let row = self
+                .row(index)
                 .iter()
-                .enumerate()
-                .map(|(i, v)| v * hidden_layer[i + target_l2])
-                .sum();
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(file, "{word} {row}").expect("write row");
+        }
+    }
 
-            let g = (1.0 - sigmoid(f)) * cbow_params.learning_rate;
+    /// Write the vectors in the canonical word2vec binary format: the same
+    /// header line, then for each entry the UTF-8 word, a single space, and
+    /// `dim` little-endian `f32`s packed contiguously.
+    fn save_word2vec_binary(&self, file_path: &str) {
+        let mut file = open_or_create_file(file_path);
+        let vocab_size = self.words_map.len();
+        writeln!(file, "{} {}", vocab_size, self.embeddings_dimension).expect("write header");
+        for (index, word) in self.words_by_index().into_iter().enumerate() {
+            file.write_all(word.as_bytes()).expect("write word");
+            file.write_all(b" ").expect("write separator");
+            for v in self.row(index) {
+                file.write_all(&v.to_le_bytes()).expect("write float");
+            }
+        }
+    }
 
-            let mut neu1e: Vec<f32> = (0..cbow_params.embeddings_dimension)
-                .map(|c| g * hidden_layer[c + target_l2])
-                .collect();
-            (0..cbow_params.embeddings_dimension)
-                .for_each(|c| hidden_layer[c + target_l2] += g * neu1[c]);
+    /// Reconstruct `words_map` and the matrix from a word2vec text file.
+    fn read_word2vec_text(file_path: &str) -> Self {
+        let contents = read_to_string(file_path).expect("read embeddings file");
+        let mut lines = contents.lines();
+        let header = lines.next().expect("missing header");
+        let embeddings_dimension = header
+            .split_whitespace()
+            .nth(1)
+            .and_then(|d| d.parse().ok())
+            .expect("invalid header");
+        let mut words_map = HashMap::new();
+        let mut matrix = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let mut parts = line.split_whitespace();
+            let word = parts.next().expect("missing word");
+            words_map.insert(word.to_string(), index);
+            matrix.extend(parts.map(|v| v.parse::<f32>().expect("invalid float")));
+        }
+        Self::from_rows(words_map, matrix, embeddings_dimension)
+    }
 
-            for negative_target in cbow_params.get_random_indices(&target, &corpus.vec) {
-                let l2 = negative_target * cbow_params.embeddings_dimension;
-                let f: f32 = neu1
-                    .iter()
-                    .enumerate()
-                    .map(|(i, v)| v * hidden_layer[i + l2])
-                    .sum();
+    /// Reconstruct `words_map` and the matrix from a word2vec binary file.
+    fn read_word2vec_binary(file_path: &str) -> Self {
+        let bytes = std::fs::read(file_path).expect("read embeddings file");
+        let newline = bytes.iter().position(|b| *b == b'\n').expect("missing header");
+        let header = std::str::from_utf8(&bytes[..newline]).expect("invalid header");
+        let mut header_parts = header.split_whitespace();
+        let vocab_size: usize = header_parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .expect("invalid header");
+        let embeddings_dimension: usize = header_parts
+            .next()
+            .and_then(|d| d.parse().ok())
+            .expect("invalid header");
+        let mut words_map = HashMap::new();
+        let mut matrix = Vec::with_capacity(vocab_size * embeddings_dimension);
+        let mut cursor = newline + 1;
+        for index in 0..vocab_size {
+            let space = bytes[cursor..]
+                .iter()
+                .position(|b| *b == b' ')
+                .expect("missing word separator")
+                + cursor;
+            let word = std::str::from_utf8(&bytes[cursor..space]).expect("invalid word");
+            words_map.insert(word.to_string(), index);
+            cursor = space + 1;
+            for _ in 0..embeddings_dimension {
+                let raw = [
+                    bytes[cursor],
+                    bytes[cursor + 1],
+                    bytes[cursor + 2],
+                    bytes[cursor + 3],
+                ];
+                matrix.push(f32::from_le_bytes(raw));
+                cursor += 4;
+            }
+        }
+        Self::from_rows(words_map, matrix, embeddings_dimension)
+    }
+
+    /// Score `query` against every vocabulary row by cosine similarity (the dot
+    /// product divided by both norms, so rows stored verbatim by
+    /// [`Embeddings::from_rows`] are scored the same way), skip the `exclude`d
+    /// indices, and pop the `k` best off a max-heap.
+    fn top_k(&self, query: Vec<f32>, k: usize, exclude: &[usize]) -> Vec<(String, f32)> {
+        let index_to_word: HashMap<usize, &String> =
+            self.words_map.iter().map(|(w, i)| (*i, w)).collect();
+        let query_norm = query.iter().map(|v| v * v).sum::<f32>().sqrt();
+        let mut heap: BinaryHeap<Scored> = self
+            .matrix
+            .chunks(self.embeddings_dimension)
+            .take(self.words_map.len())
+            .enumerate()
+            .filter(|(index, _)| !exclude.contains(index))
+            .map(|(index, row)| {
+                let dot: f32 = query.iter().zip(row).map(|(a, b)| a * b).sum();
+                let norm = query_norm * row.iter().map(|v| v * v).sum::<f32>().sqrt();
+                let score = if norm > 0.0 { dot / norm } else { 0.0 };
+                Scored { index, score }
+            })
+            .collect();
+        std::iter::from_fn(|| heap.pop())
+            .take(k)
+            .map(|s| (index_to_word[&s.index].clone(), s.score))
+            .collect()
+    }
+}
+
+/// Squared Euclidean distance between two equal-length slices.
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
 
-                let g = (0.0 - sigmoid(f)) * cbow_params.learning_rate;
+/// Index of the nearest of `k` flattened `d`-length centroids to `point`.
+fn nearest_centroid(point: &[f32], centroids: &[f32], k: usize, d: usize) -> usize {
+    (0..k)
+        .min_by(|&a, &b| {
+            squared_distance(point, &centroids[a * d..a * d + d])
+                .total_cmp(&squared_distance(point, &centroids[b * d..b * d + d]))
+        })
+        .unwrap()
+}
+
+/// Lloyd's algorithm over one PQ subspace. Returns the `k` centroids flattened
+/// into a single `k * d` buffer, seeded from random subvectors.
+fn kmeans_subspace(subvectors: &[Vec<f32>], k: usize, d: usize, max_iters: usize) -> Vec<f32> {
+    let mut rng = thread_rng();
+    let mut centroids: Vec<f32> = subvectors
+        .choose_multiple(&mut rng, k.min(subvectors.len()))
+        .flat_map(|v| v.iter().copied())
+        .collect();
+    centroids.resize(k * d, 0.0);
 
-                (0..cbow_params.embeddings_dimension)
-                    .for_each(|c| neu1e[c] += g * hidden_layer[c + l2]);
-                (0..cbow_params.embeddings_dimension)
-                    .for_each(|c| hidden_layer[c + l2] += g * neu1[c]);
+    let mut assignments = vec![usize::MAX; subvectors.len()];
+    for _ in 0..max_iters {
+        let mut changed = false;
+        for (i, sv) in subvectors.iter().enumerate() {
+            let nearest = nearest_centroid(sv, &centroids, k, d);
+            if nearest != assignments[i] {
+                assignments[i] = nearest;
+                changed = true;
             }
+        }
+        let mut sums = vec![0.0f32; k * d];
+        let mut counts = vec![0usize; k];
+        for (i, sv) in subvectors.iter().enumerate() {
+            let c = assignments[i];
+            counts[c] += 1;
+            for (j, value) in sv.iter().enumerate() {
+                sums[c * d + j] += value;
+            }
+        }
+        for c in 0..k {
+            if counts[c] > 0 {
+                for j in 0..d {
+                    centroids[c * d + j] = sums[c * d + j] / counts[c] as f32;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    centroids
+}
 
-            // backpropagation, pass the hidden layer to the input layer
-            context.iter().for_each(|index| {
-                (0..cbow_params.embeddings_dimension).for_each(|c| {
-                    input_layer[c + index * cbow_params.embeddings_dimension] += neu1e[c]
+/// Number of centroids learned per PQ subspace; fits in a single byte.
+const PQ_CENTROIDS: usize = 256;
+
+/// A product-quantized view of trained embeddings: each row is stored as `m`
+/// centroid indices (one byte each) into `m` learned codebooks, shrinking each
+/// row from `4 * embeddings_dimension` bytes of floats to `m` bytes.
+struct QuantizedEmbeddings {
+    words_map: HashMap<String, usize>,
+    embeddings_dimension: usize,
+    m: usize,
+    d: usize,
+    codebooks: Vec<Vec<f32>>,
+    codes: Vec<Vec<u8>>,
+}
+
+impl QuantizedEmbeddings {
+    /// Reconstruct the approximate embedding of the word at `index` by
+    /// concatenating its per-subspace centroids.
+    fn reconstruct_index(&self, index: usize) -> Vec<f32> {
+        let code = &self.codes[index];
+        let mut vector = Vec::with_capacity(self.embeddings_dimension);
+        for (centroid, codebook) in code.iter().zip(&self.codebooks) {
+            let start = *centroid as usize * self.d;
+            vector.extend_from_slice(&codebook[start..start + self.d]);
+        }
+        vector
+    }
+
+    /// Approximate embedding of `word`, or `None` if it is unknown.
+    fn reconstruct(&self, word: &str) -> Option<Vec<f32>> {
+        self.words_map.get(word).map(|i| self.reconstruct_index(*i))
+    }
+
+    /// Feed the reconstructed vectors back into the cosine-similarity API.
+    fn most_similar(&self, word: &str, k: usize) -> Vec<(String, f32)> {
+        let matrix: Vec<f32> = (0..self.words_map.len())
+            .flat_map(|i| self.reconstruct_index(i))
+            .collect();
+        Embeddings::new(self.words_map.clone(), matrix, self.embeddings_dimension).most_similar(word, k)
+    }
+
+    /// Serialize the codebooks and per-word codes instead of the full float
+    /// matrix.
+    fn save(&self, file_path: &str) {
+        let value = json!({
+            "embeddings_dimension": self.embeddings_dimension,
+            "m": self.m,
+            "d": self.d,
+            "codebooks": self.codebooks,
+            "codes": self.codes,
+            "words": self.words_map,
+        });
+        let mut file = open_or_create_file(file_path);
+        file.write_all(serde_json::to_string_pretty(&value).unwrap().as_bytes())
+            .expect("write quantized embeddings");
+    }
+}
+
+impl Embeddings {
+    /// Group the vocabulary into `k` semantic clusters with Lloyd's algorithm
+    /// over the `dim`-dimensional rows. Centers are seeded with k-means++, then
+    /// assignment and recomputation alternate until assignments stop changing or
+    /// `max_iters` is hit; empty clusters are re-seeded from the farthest point.
+    /// Returns a word→cluster map and the flattened `k * dim` centroids.
+    fn cluster(&self, k: usize, max_iters: usize) -> (HashMap<String, usize>, Vec<f32>) {
+        let dim = self.embeddings_dimension;
+        let n = self.words_map.len();
+        let rows: Vec<&[f32]> = (0..n).map(|i| self.row(i)).collect();
+        let mut rng = thread_rng();
+
+        // k-means++ seeding: first center at random, the rest with probability
+        // proportional to squared distance from the nearest chosen center.
+        let mut centroids: Vec<f32> = Vec::with_capacity(k * dim);
+        centroids.extend_from_slice(rows[rng.gen_range(0..n)]);
+        while centroids.len() < k * dim {
+            let chosen = centroids.chunks(dim);
+            let dists: Vec<f32> = rows
+                .iter()
+                .map(|row| {
+                    chosen
+                        .clone()
+                        .map(|center| squared_distance(row, center))
+                        .fold(f32::INFINITY, f32::min)
                 })
-            });
+                .collect();
+            let total: f32 = dists.iter().sum();
+            let picked = if total > 0.0 {
+                let mut target = rng.gen::<f32>() * total;
+                dists
+                    .iter()
+                    .position(|dist| {
+                        target -= dist;
+                        target <= 0.0
+                    })
+                    .unwrap_or(n - 1)
+            } else {
+                rng.gen_range(0..n)
+            };
+            centroids.extend_from_slice(rows[picked]);
+        }
+
+        let mut assignments = vec![usize::MAX; n];
+        for _ in 0..max_iters {
+            let mut changed = false;
+            for (i, row) in rows.iter().enumerate() {
+                let nearest = nearest_centroid(row, &centroids, k, dim);
+                if nearest != assignments[i] {
+                    assignments[i] = nearest;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+
+            let mut sums = vec![0.0f32; k * dim];
+            let mut counts = vec![0usize; k];
+            for (i, row) in rows.iter().enumerate() {
+                let c = assignments[i];
+                counts[c] += 1;
+                for (j, value) in row.iter().enumerate() {
+                    sums[c * dim + j] += value;
+                }
+            }
+            for c in 0..k {
+                if counts[c] > 0 {
+                    for j in 0..dim {
+                        centroids[c * dim + j] = sums[c * dim + j] / counts[c] as f32;
+                    }
+                } else {
+                    // Re-seed an empty cluster from the point farthest from its
+                    // own current centroid.
+                    let farthest = (0..n)
+                        .max_by(|&a, &b| {
+                            let center = |i: usize| {
+                                let start = assignments[i] * dim;
+                                &centroids[start..start + dim]
+                            };
+                            squared_distance(rows[a], center(a))
+                                .total_cmp(&squared_distance(rows[b], center(b)))
+                        })
+                        .unwrap();
+                    centroids[c * dim..c * dim + dim].copy_from_slice(rows[farthest]);
+                }
+            }
+        }
+
+        let clusters = self
+            .words_map
+            .iter()
+            .map(|(word, index)| (word.clone(), assignments[*index]))
+            .collect();
+        (clusters, centroids)
+    }
+
+    /// Learn a product quantizer with `m` subspaces and encode every word.
+    /// `embeddings_dimension` must be divisible by `m`.
+    fn quantize(&self, m: usize) -> QuantizedEmbeddings {
+        let dim = self.embeddings_dimension;
+        assert!(
+            dim.is_multiple_of(m),
+            "embeddings_dimension ({dim}) must be divisible by m ({m})"
+        );
+        let d = dim / m;
+        let vocab = self.words_map.len();
+
+        let codebooks: Vec<Vec<f32>> = (0..m)
+            .map(|subspace| {
+                let subvectors: Vec<Vec<f32>> = (0..vocab)
+                    .map(|row| {
+                        let start = row * dim + subspace * d;
+                        self.matrix[start..start + d].to_vec()
+                    })
+                    .collect();
+                kmeans_subspace(&subvectors, PQ_CENTROIDS, d, 25)
+            })
+            .collect();
+
+        let codes: Vec<Vec<u8>> = (0..vocab)
+            .map(|row| {
+                (0..m)
+                    .map(|subspace| {
+                        let start = row * dim + subspace * d;
+                        nearest_centroid(
+                            &self.matrix[start..start + d],
+                            &codebooks[subspace],
+                            PQ_CENTROIDS,
+                            d,
+                        ) as u8
+                    })
+                    .collect()
+            })
+            .collect();
+
+        QuantizedEmbeddings {
+            words_map: self.words_map.clone(),
+            embeddings_dimension: dim,
+            m,
+            d,
+            codebooks,
+            codes,
         }
     }
 }
@@ -260,27 +1060,82 @@ fn main() {
     let raw_corpus = "Today we will be learning about the fundamentals of data science and statistics. Data Science and statistics are hot and growing fields with alternative names of machine learning, artificial intelligence, big data, etc. I'm really excited to talk to you about data science and statistics because data science and statistics have long been a passions of mine. I didn't used to be very good at data science and statistics but after studying data science and statistics for a long time, I got better and better at it until I became a data science and statistics expert. I'm really excited to talk to you about data science and statistics, thanks for listening to me talk about data science and statistics.".to_string();
 
     let corpus = parse_corpus(raw_corpus);
-    let cbow_params = CBOWParams::new(corpus.words_map.len())
+    let vocab_size = corpus.words_map.len();
+    let cbow_params = CBOWParams::new(vocab_size)
         .set_embeddings_dimension(100)
+        .set_window_size(2)
+        .set_random_samples(30)
+        .set_mean(0.0)
+        .set_std_dev(0.01)
         .set_epochs(300)
-        .set_learning_rate(0.01);
+        .set_learning_rate(0.01)
+        .set_min_learning_rate(0.0001)
+        .set_subword(3, 6, 50_000)
+        .set_threads(1);
     let pairs = cbow_params.generate_pairs(&corpus.vec);
-    let (mut input_layer, hidden_layer) = cbow_params.create_matrices();
+    let (mut input_layer, mut hidden_layer) = cbow_params.create_matrices();
     train(
         &pairs,
         &cbow_params,
         &mut input_layer,
-        hidden_layer,
+        &mut hidden_layer,
         &corpus,
+        |epoch, loss| println!("epoch {epoch}: loss {loss}"),
     );
 
     let values = corpus
         .words_map
-        .into_iter()
-        .map(|(k, v)| generate_result(&k, &v, &input_layer, cbow_params.embeddings_dimension))
+        .iter()
+        .map(|(k, v)| generate_result(k, v, &input_layer, cbow_params.embeddings_dimension))
         .collect();
 
-    save_changes("result.json", values)
+    save_changes("result.json", values);
+
+    let embeddings = Embeddings::with_subword(
+        corpus.words_map,
+        input_layer,
+        cbow_params.embeddings_dimension,
+        Subword {
+            min_n: cbow_params.min_n,
+            max_n: cbow_params.max_n,
+            buckets: cbow_params.buckets,
+            vocab_size,
+        },
+    );
+
+    println!("most similar to 'data':");
+    for (word, score) in embeddings.most_similar("data", 5) {
+        println!("  {word}: {score}");
+    }
+
+    println!("data : science :: statistics : ?");
+    for (word, score) in embeddings.analogy("data", "science", "statistics", 5) {
+        println!("  {word}: {score}");
+    }
+
+    let (clusters, _centroids) = embeddings.cluster(3, 50);
+    println!("word clusters:");
+    for (word, cluster) in &clusters {
+        println!("  {word} -> {cluster}");
+    }
+
+    // Round-trip through the canonical word2vec formats so the vectors are
+    // usable from other tooling and reloadable without retraining.
+    embeddings.save_word2vec_text("vectors.txt");
+    embeddings.save_word2vec_binary("vectors.bin");
+    let _from_text = Embeddings::read_word2vec_text("vectors.txt");
+    let _from_binary = Embeddings::read_word2vec_binary("vectors.bin");
+
+    // Compress with product quantization and query from the compact view.
+    let quantized = embeddings.quantize(10);
+    quantized.save("quantized.json");
+    if let Some(vector) = quantized.reconstruct("data") {
+        println!("reconstructed 'data' has {} dimensions", vector.len());
+    }
+    println!("most similar to 'data' (quantized):");
+    for (word, score) in quantized.most_similar("data", 5) {
+        println!("  {word}: {score}");
+    }
 }
 
 #[cfg(test)]
@@ -325,6 +1180,24 @@ mod tests {
         assert_eq!(target, 2);
     }
 
+    #[test]
+    fn test_learning_rate_decays_linearly_to_floor() {
+        let cbow_params = CBOWParams::new(4)
+            .set_epochs(11)
+            .set_learning_rate(0.1)
+            .set_min_learning_rate(0.0);
+        assert_eq!(cbow_params.learning_rate_at(0), 0.1);
+        assert!((cbow_params.learning_rate_at(5) - 0.05).abs() < 1e-6);
+        assert_eq!(cbow_params.learning_rate_at(10), 0.0);
+    }
+
+    #[test]
+    fn test_learning_rate_constant_without_floor() {
+        let cbow_params = CBOWParams::new(4).set_epochs(10).set_learning_rate(0.1);
+        assert_eq!(cbow_params.learning_rate_at(0), 0.1);
+        assert_eq!(cbow_params.learning_rate_at(9), 0.1);
+    }
+
     #[test]
     fn test_get_context_embedding() {
         let embeddings_dimension = 4;
@@ -333,7 +1206,156 @@ mod tests {
             0.1, 0.1, 0.1,
         ];
         let context = [0, 1, 3, 4];
-        let context_embedding = get_context_embedding(&context, embeddings_dimension, &embeddings);
-        assert_eq!(context_embedding, vec![0.7000000000000001, 0.5, 0.6, 0.4]);
+        let input_rows: Vec<Vec<usize>> = (0..5).map(|i| vec![i]).collect();
+        let context_embedding =
+            get_context_embedding(&context, embeddings_dimension, &embeddings, &input_rows);
+        let expected = [0.7, 0.5, 0.6, 0.4];
+        assert_eq!(context_embedding.len(), expected.len());
+        for (actual, expected) in context_embedding.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-6);
+        }
+    }
+
+    fn sample_embeddings() -> Embeddings {
+        let words_map: HashMap<String, usize> = HashMap::from([
+            ("king".into(), 0),
+            ("queen".into(), 1),
+            ("man".into(), 2),
+            ("woman".into(), 3),
+        ]);
+        // Rows laid out so king/queen and man/woman point in similar directions.
+        let input_layer = vec![
+            1.0, 1.0, 0.9, 1.1, 1.0, 0.0, 0.9, 0.1,
+        ];
+        Embeddings::new(words_map, input_layer, 2)
+    }
+
+    #[test]
+    fn test_subword_indices_land_in_bucket_region() {
+        let indices = subword_indices("where", 3, 4, 100, 10);
+        // "<where>" has 7 chars, so 5 tri-grams and 4 four-grams.
+        assert_eq!(indices.len(), 9);
+        assert!(indices.iter().all(|i| (10..110).contains(i)));
+    }
+
+    #[test]
+    fn test_subword_embeds_oov_word() {
+        let words_map: HashMap<String, usize> = HashMap::from([("where".into(), 0)]);
+        let vocab_size = words_map.len();
+        let buckets = 16;
+        let dim = 2;
+        // One word row plus `buckets` bucket rows.
+        let mut input_layer = vec![1.0, 0.0];
+        input_layer.extend(std::iter::repeat_n(0.5, buckets * dim));
+        let embeddings = Embeddings::with_subword(
+            words_map,
+            input_layer,
+            dim,
+            Subword {
+                min_n: 3,
+                max_n: 4,
+                buckets,
+                vocab_size,
+            },
+        );
+        // Unknown word still gets a vector from its bucket rows.
+        assert!(embeddings.embedding_for("wherever").is_some());
+    }
+
+    #[test]
+    fn test_most_similar_excludes_query_and_ranks_by_cosine() {
+        let embeddings = sample_embeddings();
+        let similar = embeddings.most_similar("king", 3);
+        assert_eq!(similar.len(), 3);
+        assert!(similar.iter().all(|(w, _)| w != "king"));
+        assert_eq!(similar[0].0, "queen");
+    }
+
+    #[test]
+    fn test_most_similar_unknown_word_is_empty() {
+        let embeddings = sample_embeddings();
+        assert!(embeddings.most_similar("dragon", 3).is_empty());
+    }
+
+    #[test]
+    fn test_cluster_assigns_every_word() {
+        let embeddings = sample_embeddings();
+        let (clusters, centroids) = embeddings.cluster(2, 10);
+        assert_eq!(clusters.len(), 4);
+        assert!(clusters.values().all(|c| *c < 2));
+        assert_eq!(centroids.len(), 2 * embeddings.embeddings_dimension);
+    }
+
+    #[test]
+    fn test_quantize_reconstructs_full_dimension() {
+        let embeddings = sample_embeddings();
+        let quantized = embeddings.quantize(2);
+        let reconstructed = quantized.reconstruct("king").unwrap();
+        assert_eq!(reconstructed.len(), 2);
+        assert!(quantized.reconstruct("dragon").is_none());
+        // With only four distinct rows the codebooks capture them exactly, so
+        // the nearest word to "king" is still "queen".
+        assert_eq!(quantized.most_similar("king", 1)[0].0, "queen");
+    }
+
+    #[test]
+    fn test_word2vec_text_round_trip() {
+        let embeddings = sample_embeddings();
+        let path = std::env::temp_dir().join("w2v_roundtrip.txt");
+        let path = path.to_str().unwrap();
+        embeddings.save_word2vec_text(path);
+        let loaded = Embeddings::read_word2vec_text(path);
+        assert_eq!(loaded.words_map, embeddings.words_map);
+        assert_eq!(loaded.matrix, embeddings.matrix);
+    }
+
+    #[test]
+    fn test_word2vec_binary_round_trip() {
+        let embeddings = sample_embeddings();
+        let path = std::env::temp_dir().join("w2v_roundtrip.bin");
+        let path = path.to_str().unwrap();
+        embeddings.save_word2vec_binary(path);
+        let loaded = Embeddings::read_word2vec_binary(path);
+        assert_eq!(loaded.words_map, embeddings.words_map);
+        assert_eq!(loaded.matrix, embeddings.matrix);
+    }
+
+    #[test]
+    fn test_train_multithreaded_mutates_matrices_and_reports_loss() {
+        let corpus = parse_corpus("uno dos tres uno dos tres uno dos".to_string());
+        let cbow_params = CBOWParams::new(corpus.words_map.len())
+            .set_embeddings_dimension(8)
+            .set_epochs(5)
+            .set_learning_rate(0.05)
+            .set_threads(2);
+        let pairs = cbow_params.generate_pairs(&corpus.vec);
+        let (mut input_layer, mut hidden_layer) = cbow_params.create_matrices();
+        let input_before = input_layer.clone();
+
+        let mut epochs_seen = Vec::new();
+        train(
+            &pairs,
+            &cbow_params,
+            &mut input_layer,
+            &mut hidden_layer,
+            &corpus,
+            |epoch, loss| epochs_seen.push((epoch, loss)),
+        );
+
+        // The callback fired once per epoch even though threads > 1.
+        assert_eq!(epochs_seen.len(), 5);
+        assert!(epochs_seen.iter().all(|(_, loss)| loss.is_finite()));
+        // Hogwild updates actually touched the shared input matrix.
+        assert_ne!(input_layer, input_before);
+    }
+
+    #[test]
+    fn test_analogy_excludes_inputs() {
+        let embeddings = sample_embeddings();
+        let result = embeddings.analogy("man", "king", "woman", 4);
+        assert!(result
+            .iter()
+            .all(|(w, _)| w != "man" && w != "king" && w != "woman"));
+        assert_eq!(result[0].0, "queen");
     }
 }